@@ -1,4 +1,7 @@
 use std::env::args;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 use std::process::exit;
 
 fn main() {
@@ -37,20 +40,65 @@ where
                 ),
             },
         },
+        ["find-new", directory, snapshot_file] => match find_new(directory, snapshot_file) {
+            Err(e) => (2, Some(e.to_string())),
+            Ok(added) => {
+                if added.is_empty() {
+                    (0, None)
+                } else {
+                    let message = added
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    (1, Some(message))
+                }
+            }
+        },
         _ => (1, Some(help_text(&app))),
     }
 }
 
+/// Scans `directory` for tagged directories, compares them against the ones recorded in
+/// `snapshot_file`, writes the up to date list back to `snapshot_file`, and returns the tagged
+/// directories that weren't present in the previous snapshot.
+fn find_new(directory: &str, snapshot_file: &str) -> io::Result<Vec<PathBuf>> {
+    let previous = load_snapshot(snapshot_file)?;
+    let root = fs::canonicalize(directory)?;
+    let current = cachedir::find_tags(&root)?;
+    save_snapshot(snapshot_file, &current)?;
+    Ok(cachedir::diff_tags(&previous, &current).added)
+}
+
+fn load_snapshot(path: &str) -> io::Result<Vec<PathBuf>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(PathBuf::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save_snapshot(path: &str, tags: &[PathBuf]) -> io::Result<()> {
+    let contents = tags
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    fs::write(path, contents)
+}
+
 fn help_text<T: AsRef<str>>(binary: T) -> String {
     let binary = binary.as_ref();
     format!(
         "Usage:
-{} --help               Print this help message
-{} is-tagged DIRECTORY  Check if the directory is tagged or not
+{} --help                             Print this help message
+{} is-tagged DIRECTORY                Check if the directory is tagged or not
+{} find-new DIRECTORY SNAPSHOT_FILE   Scan DIRECTORY for tags not present in SNAPSHOT_FILE,
+                                        updating SNAPSHOT_FILE with the current set afterwards
 
 Application version: 0.3.0
 ",
-        binary, binary,
+        binary, binary, binary,
     )
 }
 
@@ -87,3 +135,35 @@ fn is_tagged_works() {
     let (exit_code, _output) = app(vec!["binary", "is-tagged", &directory_str]);
     assert!(exit_code != 0);
 }
+
+#[test]
+fn find_new_works() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory_str = directory.path().to_str().unwrap().to_string();
+    let snapshot_file = directory.path().with_extension("snapshot");
+    let snapshot_file_str = snapshot_file.to_str().unwrap().to_string();
+
+    // No tags yet, nothing new.
+    let (exit_code, output) =
+        app(vec!["binary", "find-new", &directory_str, &snapshot_file_str]);
+    assert_eq!((exit_code, output), (0, None));
+
+    let cache = directory.path().join("cache");
+    fs::create_dir(&cache).unwrap();
+    cachedir::add_tag(&cache).unwrap();
+
+    // A tag appeared since the last snapshot was taken.
+    let (exit_code, output) =
+        app(vec!["binary", "find-new", &directory_str, &snapshot_file_str]);
+    assert_eq!(
+        (exit_code, output),
+        (1, Some(cache.canonicalize().unwrap().display().to_string()))
+    );
+
+    // The snapshot has been updated, so running again reports nothing new.
+    let (exit_code, output) =
+        app(vec!["binary", "find-new", &directory_str, &snapshot_file_str]);
+    assert_eq!((exit_code, output), (0, None));
+
+    fs::remove_file(&snapshot_file).unwrap();
+}
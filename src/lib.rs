@@ -12,7 +12,7 @@
 //! > they create, for easy identification by backup systems and other data management utilities.
 //! > Data management utilities can then heed or ignore these tags as the user sees fit.
 use std::io::prelude::*;
-use std::{env, fs, io, path};
+use std::{env, ffi, fs, io, iter, path, time};
 
 /// The `CACHEDIR.TAG` file header as defined by the specification.
 pub const HEADER: &[u8; 43] = b"Signature: 8a477f597d28d172789f06886806bc55";
@@ -35,6 +35,11 @@ pub fn is_tagged<P: AsRef<path::Path>>(directory: P) -> io::Result<bool> {
 
 /// Gets the state of the tag in the specified directory.
 ///
+/// Only the 43-byte signature at the start of the file is checked: the specification allows (and
+/// recommends) arbitrary human-readable content after it, so a `CACHEDIR.TAG` with a correct
+/// header followed by a body (see [add_tag_with_body](fn.add_tag_with_body.html)) is still
+/// `TagState::Present`.
+///
 /// Will return an error if:
 ///
 /// * The directory can't be accessed for any reason (it doesn't exist, permission error etc.)
@@ -66,6 +71,7 @@ pub fn get_tag_state<P: AsRef<path::Path>>(directory: P) -> io::Result<TagState>
 }
 
 /// The state of a `CACHEDIR.TAG` file.
+#[derive(Debug)]
 pub enum TagState {
     /// The file doesn't exist.
     Absent,
@@ -95,6 +101,41 @@ pub fn add_tag<P: AsRef<path::Path>>(directory: P) -> io::Result<()> {
     }
 }
 
+/// A standard human-readable body applications can pass to
+/// [add_tag_with_body](fn.add_tag_with_body.html), explaining what the file is for to anyone who
+/// stumbles on it.
+pub const DEFAULT_TAG_BODY: &str = "\
+This file is a cache directory tag created by the library at https://github.com/jstasiak/cachedir.
+For information about cache directory tags see https://bford.info/cachedir/\n";
+
+/// Adds a tag to the specified `directory`, like [add_tag](fn.add_tag.html), but with `body`
+/// written after the signature as human-readable content explaining what the directory is for.
+///
+/// The tagging specification explicitly allows (and recommends) such a body, so that sysadmins who
+/// stumble on the file understand what it is; see [DEFAULT_TAG_BODY](constant.DEFAULT_TAG_BODY.html)
+/// for a body applications can use out of the box.
+///
+/// Will return an error if:
+///
+/// * The `directory` exists and contains a `CACHEDIR.TAG` file, regardless of its content.
+/// * The file can't be created for any reason (the `directory` doesn't exist, permission error,
+///   can't write to the file etc.)
+pub fn add_tag_with_body<P: AsRef<path::Path>>(directory: P, body: &str) -> io::Result<()> {
+    let directory = directory.as_ref();
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(directory.join("CACHEDIR.TAG"))
+    {
+        Ok(mut cachedir_tag) => {
+            cachedir_tag.write_all(HEADER)?;
+            cachedir_tag.write_all(b"\n")?;
+            cachedir_tag.write_all(body.as_bytes())
+        }
+        Err(e) => Err(e),
+    }
+}
+
 /// Ensures the tag exists in `directory`.
 ///
 /// This function considers the `CACHEDIR.TAG` file in `directory` existing, regardless of its
@@ -151,6 +192,396 @@ pub fn mkdir_atomic<P: AsRef<path::Path>>(directory: P) -> io::Result<bool> {
     }
 }
 
+/// An entry yielded while [`walk`]ing a directory tree.
+pub struct WalkEntry {
+    /// The full path of this entry.
+    pub path: path::PathBuf,
+    /// `true` if this entry is the `CACHEDIR.TAG` file of a tagged directory.
+    pub is_tag: bool,
+    /// If this entry is the directory of a `CACHEDIR.TAG` or the tag file itself, the path of
+    /// that tagged directory. `None` for every other entry.
+    pub tagged_root: Option<path::PathBuf>,
+}
+
+enum PendingEntry {
+    Path(path::PathBuf),
+    TagFile {
+        path: path::PathBuf,
+        tagged_root: path::PathBuf,
+    },
+}
+
+/// A depth-first directory tree walker, created with [walk](fn.walk.html).
+///
+/// Whenever the walk enters a directory containing a valid `CACHEDIR.TAG`, it yields the
+/// directory and its `CACHEDIR.TAG` file but does not descend into the rest of the directory's
+/// contents, which is the behavior backup tools typically want: include the tag marker itself but
+/// skip the cached payload it marks.
+///
+/// Unreadable directories don't abort the walk: they're surfaced as `Err` entries and the walk
+/// continues with whatever else is left on the stack.
+pub struct Walk {
+    stack: Vec<PendingEntry>,
+    follow_symlinks: bool,
+}
+
+impl Walk {
+    /// Controls whether symlinked directories are descended into.
+    ///
+    /// Defaults to `false`, so that a symlink cycle can't send the walk into an infinite loop.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    fn visit(&mut self, path: path::PathBuf) -> io::Result<WalkEntry> {
+        let metadata = if self.follow_symlinks {
+            fs::metadata(&path)
+        } else {
+            fs::symlink_metadata(&path)
+        }?;
+
+        if !metadata.is_dir() {
+            return Ok(WalkEntry {
+                path,
+                is_tag: false,
+                tagged_root: None,
+            });
+        }
+
+        match get_tag_state(&path)? {
+            TagState::Present => {
+                self.stack.push(PendingEntry::TagFile {
+                    path: path.join("CACHEDIR.TAG"),
+                    tagged_root: path.clone(),
+                });
+                Ok(WalkEntry {
+                    tagged_root: Some(path.clone()),
+                    path,
+                    is_tag: false,
+                })
+            }
+            _ => {
+                let mut children = fs::read_dir(&path)?
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<io::Result<Vec<_>>>()?;
+                // Sorted for deterministic traversal order, reversed so that popping the stack
+                // (which is LIFO) still visits entries in sorted order.
+                children.sort();
+                self.stack
+                    .extend(children.into_iter().rev().map(PendingEntry::Path));
+                Ok(WalkEntry {
+                    path,
+                    is_tag: false,
+                    tagged_root: None,
+                })
+            }
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = io::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.stack.pop()?;
+        Some(match entry {
+            PendingEntry::TagFile { path, tagged_root } => Ok(WalkEntry {
+                path,
+                is_tag: true,
+                tagged_root: Some(tagged_root),
+            }),
+            PendingEntry::Path(path) => self.visit(path),
+        })
+    }
+}
+
+/// Walks the directory tree rooted at `root`, depth-first, pruning tagged cache subtrees.
+///
+/// Whenever the walk enters a directory containing a valid `CACHEDIR.TAG` (per
+/// [get_tag_state](fn.get_tag_state.html)), it yields the directory itself and its
+/// `CACHEDIR.TAG` file, then skips the rest of that directory's contents instead of descending
+/// into it. This matches what backup tools want: the tag marker is kept so the directory is still
+/// visible, but the cached payload underneath it isn't.
+///
+/// By default symlinked directories aren't followed, to avoid cycles; use
+/// [Walk::follow_symlinks](struct.Walk.html#method.follow_symlinks) to change that.
+///
+/// Unreadable subdirectories don't abort the walk: they're surfaced as `Err` entries.
+pub fn walk<P: AsRef<path::Path>>(root: P) -> Walk {
+    Walk {
+        stack: vec![PendingEntry::Path(root.as_ref().to_path_buf())],
+        follow_symlinks: false,
+    }
+}
+
+/// Returns every tagged directory found while [walk](fn.walk.html)ing the tree rooted at `root`.
+pub fn find_tags<P: AsRef<path::Path>>(root: P) -> io::Result<Vec<path::PathBuf>> {
+    let mut tags = Vec::new();
+    for entry in walk(root) {
+        let entry = entry?;
+        if entry.is_tag {
+            if let Some(tagged_root) = entry.tagged_root {
+                tags.push(tagged_root);
+            }
+        }
+    }
+    Ok(tags)
+}
+
+/// The result of comparing a previous and a current set of tagged directories, as produced by
+/// [diff_tags](fn.diff_tags.html).
+#[derive(Debug, PartialEq, Eq)]
+pub struct TagDiff {
+    /// Tagged directories present in `current` but not in `previous`.
+    pub added: Vec<path::PathBuf>,
+    /// Tagged directories present in `previous` but not in `current`.
+    pub removed: Vec<path::PathBuf>,
+}
+
+/// Compares a `previous` and a `current` snapshot of tagged directories (as returned by
+/// [find_tags](fn.find_tags.html)) and reports which ones appeared or disappeared.
+///
+/// This is the building block for noticing when a `CACHEDIR.TAG` suddenly shows up in a directory
+/// that was previously backed up in full: such a directory should have `added` checked before it's
+/// silently excluded from the next backup.
+pub fn diff_tags(previous: &[path::PathBuf], current: &[path::PathBuf]) -> TagDiff {
+    use std::collections::HashSet;
+
+    let previous_set: HashSet<_> = previous.iter().collect();
+    let current_set: HashSet<_> = current.iter().collect();
+
+    let mut added: Vec<_> = current
+        .iter()
+        .filter(|path| !previous_set.contains(path))
+        .cloned()
+        .collect();
+    let mut removed: Vec<_> = previous
+        .iter()
+        .filter(|path| !current_set.contains(path))
+        .cloned()
+        .collect();
+    added.sort();
+    removed.sort();
+
+    TagDiff { added, removed }
+}
+
+/// Statistics about the files removed by a [prune](fn.prune.html) run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    /// How many files were removed.
+    pub files_removed: u64,
+    /// The total size, in bytes, of the removed files.
+    pub bytes_removed: u64,
+}
+
+/// Deletes files older than `max_age` inside `directory`, returning how many files and bytes were
+/// removed.
+///
+/// `directory` must contain a valid `CACHEDIR.TAG` (see [is_tagged](fn.is_tagged.html)), or this
+/// returns an error without touching anything; this guarantees the function can never be pointed
+/// at arbitrary user data. The `CACHEDIR.TAG` file itself is never removed. Subdirectories are
+/// recursed into and removed once they become empty.
+///
+/// If `max_age` is so large that `SystemTime::now() - max_age` would underflow, this is treated as
+/// "nothing is old enough to prune" and returns empty stats rather than erroring.
+pub fn prune<P: AsRef<path::Path>>(directory: P, max_age: time::Duration) -> io::Result<PruneStats> {
+    let directory = directory.as_ref();
+    if !is_tagged(directory)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not tagged with CACHEDIR.TAG, refusing to prune it",
+                directory.display()
+            ),
+        ));
+    }
+
+    let threshold = match time::SystemTime::now().checked_sub(max_age) {
+        Some(threshold) => threshold,
+        None => return Ok(PruneStats::default()),
+    };
+
+    prune_directory(directory, threshold)
+}
+
+fn prune_directory(directory: &path::Path, threshold: time::SystemTime) -> io::Result<PruneStats> {
+    let mut stats = PruneStats::default();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_name() == ffi::OsStr::new("CACHEDIR.TAG") {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let child_stats = prune_directory(&path, threshold)?;
+            stats.files_removed += child_stats.files_removed;
+            stats.bytes_removed += child_stats.bytes_removed;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        } else if metadata.modified()? < threshold {
+            fs::remove_file(&path)?;
+            stats.files_removed += 1;
+            stats.bytes_removed += metadata.len();
+        }
+    }
+    Ok(stats)
+}
+
+/// The default maximum age used by callers of [cleanup_stale](fn.cleanup_stale.html), one hour.
+pub const STALE_TEMP_DIR_MAX_AGE: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Removes leftover temporary directories from [mkdir_atomic](fn.mkdir_atomic.html) calls that
+/// were interrupted between creating the temporary directory and renaming it into place.
+///
+/// Scans `parent` for directories whose name starts with `prefix` (the prefix `mkdir_atomic` uses
+/// is the target directory's base name) and removes those older than `max_age`, returning how many
+/// were deleted. The age gate matters: a `mkdir_atomic` call racing on the same target is still in
+/// the middle of creating its temporary directory, and it would be wrong to clobber it.
+///
+/// `parent` not existing is not an error: this returns `Ok(0)`.
+pub fn cleanup_stale<P: AsRef<path::Path>, S: AsRef<str>>(
+    parent: P,
+    prefix: S,
+    max_age: time::Duration,
+) -> io::Result<usize> {
+    let parent = parent.as_ref();
+    let prefix = prefix.as_ref();
+
+    let threshold = match time::SystemTime::now().checked_sub(max_age) {
+        Some(threshold) => threshold,
+        None => return Ok(0),
+    };
+
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        // The temporary directory's name is always longer than the prefix: tempfile appends a
+        // random suffix. This also keeps us from ever touching the real `prefix`-named directory.
+        if file_name.len() <= prefix.len() || !file_name.starts_with(prefix) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if !metadata.is_dir() || metadata.modified()? >= threshold {
+            continue;
+        }
+
+        fs::remove_dir_all(entry.path())?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Controls how [CacheStack::add_read_only](struct.CacheStack.html#method.add_read_only) reacts
+/// to a read-only layer that turns out not to be a valid `CACHEDIR.TAG` directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnUntaggedReadOnly {
+    /// Record a warning in [CacheStack::warnings](struct.CacheStack.html#structfield.warnings)
+    /// but add the layer anyway.
+    Warn,
+    /// Treat it as a hard error.
+    Reject,
+}
+
+/// A writable primary cache directory layered over an ordered list of read-only fallback cache
+/// directories.
+///
+/// Writes, as well as tagging performed through [ensure_tag](fn.ensure_tag.html)/
+/// [mkdir_atomic](fn.mkdir_atomic.html) semantics, only ever apply to the primary directory.
+/// [locate](CacheStack::locate) looks the primary up first and falls back to the read-only
+/// directories in the order they were added. This lets applications share immutable base caches
+/// across users or versions while keeping per-run writes isolated. The primary is tagged
+/// automatically and every read-only layer is checked for a valid `CACHEDIR.TAG` when it's added;
+/// with [OnUntaggedReadOnly::Warn](enum.OnUntaggedReadOnly.html) an untagged layer is still added.
+pub struct CacheStack {
+    primary: path::PathBuf,
+    read_only: Vec<path::PathBuf>,
+    /// Warnings recorded while adding read-only layers with
+    /// [OnUntaggedReadOnly::Warn](enum.OnUntaggedReadOnly.html), one per layer that wasn't a valid
+    /// `CACHEDIR.TAG` directory.
+    pub warnings: Vec<String>,
+}
+
+impl CacheStack {
+    /// Creates a new stack whose writable layer is `primary`, tagging it with `CACHEDIR.TAG` (via
+    /// [mkdir_atomic](fn.mkdir_atomic.html) if it doesn't exist yet, or
+    /// [ensure_tag](fn.ensure_tag.html) otherwise).
+    pub fn new<P: AsRef<path::Path>>(primary: P) -> io::Result<CacheStack> {
+        let primary = primary.as_ref().to_path_buf();
+        if !mkdir_atomic(&primary)? {
+            ensure_tag(&primary)?;
+        }
+        Ok(CacheStack {
+            primary,
+            read_only: Vec::new(),
+            warnings: Vec::new(),
+        })
+    }
+
+    /// Adds `directory` as a read-only fallback layer, checked after the primary and every layer
+    /// added before it.
+    ///
+    /// `directory` is expected to already contain a valid `CACHEDIR.TAG`; what happens if it
+    /// doesn't is controlled by `on_untagged`. A `directory` that can't be accessed (see
+    /// [get_tag_state](fn.get_tag_state.html)) is an error regardless of `on_untagged`.
+    pub fn add_read_only<P: AsRef<path::Path>>(
+        mut self,
+        directory: P,
+        on_untagged: OnUntaggedReadOnly,
+    ) -> io::Result<CacheStack> {
+        let directory = directory.as_ref().to_path_buf();
+        match get_tag_state(&directory)? {
+            TagState::Present => {}
+            state => match on_untagged {
+                OnUntaggedReadOnly::Reject => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is not tagged with CACHEDIR.TAG ({:?})",
+                            directory.display(),
+                            state
+                        ),
+                    ))
+                }
+                OnUntaggedReadOnly::Warn => self.warnings.push(format!(
+                    "{} is not tagged with CACHEDIR.TAG ({:?})",
+                    directory.display(),
+                    state
+                )),
+            },
+        }
+        self.read_only.push(directory);
+        Ok(self)
+    }
+
+    /// Looks up `relative_path` in the primary directory first, then in each read-only directory
+    /// in the order they were added, returning the first path that exists.
+    pub fn locate<P: AsRef<path::Path>>(&self, relative_path: P) -> Option<path::PathBuf> {
+        let relative_path = relative_path.as_ref();
+        iter::once(&self.primary)
+            .chain(self.read_only.iter())
+            .map(|directory| directory.join(relative_path))
+            .find(|candidate| candidate.exists())
+    }
+}
+
 #[test]
 fn is_tagged_on_nonexistent_directory_is_an_error() {
     let directory = path::Path::new("this directory does not exist");
@@ -175,6 +606,29 @@ fn directory_with_a_tag_with_wrong_content_is_not_tagged() {
     assert!(!is_tagged(&directory).unwrap());
 }
 
+#[test]
+fn directory_with_a_tag_followed_by_arbitrary_content_is_still_tagged() {
+    let directory = tempfile::tempdir().unwrap();
+    let cachedir_tag = directory.path().join("CACHEDIR.TAG");
+
+    let mut content = HEADER.to_vec();
+    content.extend_from_slice(b"\nSome human-readable explanation that tools should ignore.\n");
+    fs::write(&cachedir_tag, content).unwrap();
+
+    assert!(is_tagged(&directory).unwrap());
+}
+
+#[test]
+fn add_tag_with_body_is_detected_by_is_tagged() {
+    let directory = tempfile::tempdir().unwrap();
+    add_tag_with_body(directory.path(), DEFAULT_TAG_BODY).unwrap();
+    assert!(is_tagged(directory.path()).unwrap());
+
+    let contents = fs::read_to_string(directory.path().join("CACHEDIR.TAG")).unwrap();
+    assert!(contents.starts_with(std::str::from_utf8(HEADER).unwrap()));
+    assert!(contents.ends_with(DEFAULT_TAG_BODY));
+}
+
 #[test]
 fn add_tag_is_detected_by_is_tagged() {
     let directory = tempfile::tempdir().unwrap();
@@ -246,3 +700,236 @@ fn mkdir_atomic_works() {
         ["cache"],
     );
 }
+
+#[test]
+fn walk_prunes_tagged_subtrees() {
+    let root = tempfile::tempdir().unwrap();
+
+    fs::create_dir(root.path().join("plain")).unwrap();
+    fs::write(root.path().join("plain").join("file.txt"), "hello").unwrap();
+
+    let cache = root.path().join("cache");
+    fs::create_dir(&cache).unwrap();
+    add_tag(&cache).unwrap();
+    fs::write(cache.join("payload.bin"), "should not be visited").unwrap();
+
+    let entries: Vec<_> = walk(root.path()).map(|entry| entry.unwrap()).collect();
+
+    assert!(entries.iter().any(|entry| entry.path == root.path().join("plain")));
+    assert!(entries
+        .iter()
+        .any(|entry| entry.path == root.path().join("plain").join("file.txt")));
+
+    let cache_entry = entries
+        .iter()
+        .find(|entry| entry.path == cache && !entry.is_tag)
+        .unwrap();
+    assert_eq!(cache_entry.tagged_root, Some(cache.clone()));
+
+    let tag_entry = entries
+        .iter()
+        .find(|entry| entry.path == cache.join("CACHEDIR.TAG"))
+        .unwrap();
+    assert!(tag_entry.is_tag);
+    assert_eq!(tag_entry.tagged_root, Some(cache.clone()));
+
+    assert!(!entries.iter().any(|entry| entry.path == cache.join("payload.bin")));
+}
+
+#[test]
+fn walk_yields_the_root_itself_if_tagged() {
+    let root = tempfile::tempdir().unwrap();
+    add_tag(root.path()).unwrap();
+    fs::write(root.path().join("payload.bin"), "should not be visited").unwrap();
+
+    let entries: Vec<_> = walk(root.path()).map(|entry| entry.unwrap()).collect();
+
+    assert_eq!(entries.len(), 2);
+    assert!(!entries.iter().any(|entry| entry.path == root.path().join("payload.bin")));
+}
+
+#[test]
+fn find_tags_finds_every_tagged_directory() {
+    let root = tempfile::tempdir().unwrap();
+
+    let cache_a = root.path().join("a");
+    fs::create_dir(&cache_a).unwrap();
+    add_tag(&cache_a).unwrap();
+
+    fs::create_dir(root.path().join("b")).unwrap();
+
+    let cache_c = root.path().join("b").join("c");
+    fs::create_dir(&cache_c).unwrap();
+    add_tag(&cache_c).unwrap();
+
+    let mut tags = find_tags(root.path()).unwrap();
+    tags.sort();
+    let mut expected = vec![cache_a, cache_c];
+    expected.sort();
+    assert_eq!(tags, expected);
+}
+
+#[test]
+fn diff_tags_reports_added_and_removed() {
+    let previous = vec![path::PathBuf::from("/a"), path::PathBuf::from("/b")];
+    let current = vec![path::PathBuf::from("/b"), path::PathBuf::from("/c")];
+
+    let diff = diff_tags(&previous, &current);
+    assert_eq!(diff.added, vec![path::PathBuf::from("/c")]);
+    assert_eq!(diff.removed, vec![path::PathBuf::from("/a")]);
+}
+
+#[test]
+fn prune_refuses_untagged_directories() {
+    let directory = tempfile::tempdir().unwrap();
+    assert!(prune(directory.path(), time::Duration::from_secs(0)).is_err());
+}
+
+#[test]
+fn prune_removes_old_files_but_keeps_the_tag_and_recent_files() {
+    use std::thread;
+
+    let directory = tempfile::tempdir().unwrap();
+    add_tag(directory.path()).unwrap();
+
+    let old_file = directory.path().join("old.bin");
+    fs::write(&old_file, "0123456789").unwrap();
+
+    let old_subdir = directory.path().join("subdir");
+    fs::create_dir(&old_subdir).unwrap();
+    fs::write(old_subdir.join("also-old.bin"), "01234").unwrap();
+
+    // Give the filesystem a moment so that, when `prune` computes its threshold (`now - max_age`),
+    // the files above are unambiguously older than it and `recent.bin`, written right before
+    // pruning, is unambiguously newer.
+    thread::sleep(time::Duration::from_millis(50));
+    let max_age = time::Duration::from_millis(25);
+    thread::sleep(time::Duration::from_millis(50));
+
+    fs::write(directory.path().join("recent.bin"), "new").unwrap();
+
+    let stats = prune(directory.path(), max_age).unwrap();
+    assert_eq!(stats.files_removed, 2);
+    assert_eq!(stats.bytes_removed, 15);
+
+    assert!(is_tagged(directory.path()).unwrap());
+    assert!(!old_file.exists());
+    assert!(!old_subdir.exists());
+    assert!(directory.path().join("recent.bin").exists());
+}
+
+#[test]
+fn prune_is_a_no_op_when_max_age_overflows() {
+    let directory = tempfile::tempdir().unwrap();
+    add_tag(directory.path()).unwrap();
+    fs::write(directory.path().join("file.bin"), "hello").unwrap();
+
+    let stats = prune(directory.path(), time::Duration::MAX).unwrap();
+    assert_eq!(stats, PruneStats::default());
+    assert!(directory.path().join("file.bin").exists());
+}
+
+#[test]
+fn cleanup_stale_removes_only_old_matching_directories() {
+    use std::thread;
+
+    let parent = tempfile::tempdir().unwrap();
+
+    let stale = parent.path().join("cacheAbCdEf");
+    fs::create_dir(&stale).unwrap();
+
+    thread::sleep(time::Duration::from_millis(50));
+    let max_age = time::Duration::from_millis(25);
+    thread::sleep(time::Duration::from_millis(50));
+
+    let fresh = parent.path().join("cacheGhIjKl");
+    fs::create_dir(&fresh).unwrap();
+
+    // Not a match: doesn't start with the prefix.
+    fs::create_dir(parent.path().join("unrelated")).unwrap();
+    // Not a match: exactly the prefix, i.e. the real cache directory, not a temp one.
+    fs::create_dir(parent.path().join("cache")).unwrap();
+
+    let removed = cleanup_stale(parent.path(), "cache", max_age).unwrap();
+    assert_eq!(removed, 1);
+    assert!(!stale.exists());
+    assert!(fresh.exists());
+    assert!(parent.path().join("cache").exists());
+}
+
+#[test]
+fn cleanup_stale_on_nonexistent_parent_is_a_no_op() {
+    let parent = path::Path::new("this directory does not exist");
+    assert!(!parent.exists());
+    assert_eq!(cleanup_stale(parent, "cache", STALE_TEMP_DIR_MAX_AGE).unwrap(), 0);
+}
+
+#[test]
+fn cache_stack_creates_and_tags_the_primary() {
+    let root = tempfile::tempdir().unwrap();
+    let primary = root.path().join("primary");
+
+    let stack = CacheStack::new(&primary).unwrap();
+    assert!(is_tagged(&primary).unwrap());
+    assert!(stack.warnings.is_empty());
+}
+
+#[test]
+fn cache_stack_locates_primary_before_read_only_layers() {
+    let root = tempfile::tempdir().unwrap();
+
+    let primary = root.path().join("primary");
+    let read_only_a = root.path().join("read-only-a");
+    let read_only_b = root.path().join("read-only-b");
+    for directory in [&primary, &read_only_a, &read_only_b] {
+        fs::create_dir(directory).unwrap();
+        add_tag(directory).unwrap();
+    }
+
+    fs::write(read_only_a.join("shared.bin"), "a").unwrap();
+    fs::write(read_only_b.join("shared.bin"), "b").unwrap();
+    fs::write(read_only_b.join("only-in-b.bin"), "b").unwrap();
+
+    let stack = CacheStack::new(&primary)
+        .unwrap()
+        .add_read_only(&read_only_a, OnUntaggedReadOnly::Reject)
+        .unwrap()
+        .add_read_only(&read_only_b, OnUntaggedReadOnly::Reject)
+        .unwrap();
+
+    assert_eq!(stack.locate("shared.bin"), Some(read_only_a.join("shared.bin")));
+    assert_eq!(stack.locate("only-in-b.bin"), Some(read_only_b.join("only-in-b.bin")));
+    assert_eq!(stack.locate("missing.bin"), None);
+
+    fs::write(primary.join("shared.bin"), "primary").unwrap();
+    assert_eq!(stack.locate("shared.bin"), Some(primary.join("shared.bin")));
+}
+
+#[test]
+fn cache_stack_add_read_only_can_reject_untagged_directory() {
+    let root = tempfile::tempdir().unwrap();
+    let primary = root.path().join("primary");
+    let untagged = root.path().join("untagged");
+    fs::create_dir(&untagged).unwrap();
+
+    assert!(CacheStack::new(&primary)
+        .unwrap()
+        .add_read_only(&untagged, OnUntaggedReadOnly::Reject)
+        .is_err());
+}
+
+#[test]
+fn cache_stack_add_read_only_can_warn_instead_of_rejecting() {
+    let root = tempfile::tempdir().unwrap();
+    let primary = root.path().join("primary");
+    let untagged = root.path().join("untagged");
+    fs::create_dir(&untagged).unwrap();
+
+    let stack = CacheStack::new(&primary)
+        .unwrap()
+        .add_read_only(&untagged, OnUntaggedReadOnly::Warn)
+        .unwrap();
+
+    assert_eq!(stack.warnings.len(), 1);
+    assert_eq!(stack.locate("anything"), None);
+}